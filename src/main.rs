@@ -4,16 +4,36 @@
 //this happens in crt0 (C runtime zero) then this crt0 invokes the entry point of rust runtime(main fn) which is marked by the 'start'
 //language item. but our freestanding executable does have access to crt0 so we have to define our entry point
 #![no_main]
+//custom_test_frameworks lets us use #[test_case] without std's test harness (which needs
+//the standard library's test crate, unavailable in no_std); the three attributes below wire
+//our own test_runner in as the harness entry point
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 use core::panic::PanicInfo;
+mod port;
+mod serial;
 mod vga_buffer;
 // \! is the never return type to mark diverging function
 //panic info contains the file and line where the panic happened
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
     loop {}
 }
 
+//in test mode VGA isn't asserted on, so report the panic over serial and tell QEMU the
+//test run failed instead of looping forever on real hardware
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]");
+    serial_println!("Error: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
 //*Language items are special functions and types that are required internally by the compiler.
 //For example, the Copy trait is a language item that tells the compiler which types have copy semantics.
 //When we look at the implementation of copy trait, we see it has the special #[lang = "copy"] attribute that defines it as a language item.
@@ -26,15 +46,24 @@ fn panic(info: &PanicInfo) -> ! {
 
 //the start never returns because this is our os which is called by bootloader and the only way to exit is to shutdown the machine
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
     //after making static writer we can use writer directly from here(instead of carrying the instance around or call function print_something)
     use core::fmt::Write;
 
+    //turn the hardware cursor on and make it a full-height block before we start typing
+    vga_buffer::WRITER.lock().enable_cursor(0, 15);
+
+    //switch to a blinking yellow-on-blue prompt color, then back to plain colors
+    vga_buffer::WRITER.lock().set_color(vga_buffer::Color::Yellow, vga_buffer::Color::Blue);
+    vga_buffer::WRITER.lock().set_blink(true);
     vga_buffer::WRITER
         .lock()
         .write_str("hello again\n")
         .unwrap();
+    vga_buffer::WRITER.lock().set_blink(false);
+    vga_buffer::WRITER.lock().set_color(vga_buffer::Color::Black, vga_buffer::Color::White);
 
     write!(
         vga_buffer::WRITER.lock(),
@@ -49,12 +78,50 @@ pub extern "C" fn _start() -> ! {
         .lock()
         .write_str("this is write_str\n")
         .unwrap();
+    //the calls above write straight to the shadow buffer, so push them to hardware now
+    vga_buffer::WRITER.lock().flush();
 
     //use our custom println!
     //as this is our crate we don't have to write use crate::println!
     println!("Hello println {}", "!");
+    //only the panic message below is printed after this, so turn the cursor back off
+    vga_buffer::WRITER.lock().disable_cursor();
     //test panic handler
     panic!("Error hapenned");
 
     loop {}
 }
+
+//entry point used when the binary is built as the `#[cfg(test)]` integration-test harness
+//instead of the normal kernel; just runs the #[test_case] functions and exits QEMU
+#[cfg(test)]
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    loop {}
+}
+
+/// Exit code written to the `isa-debug-exit` device; QEMU maps these to its own process
+/// exit code as `(value << 1) | 1`, so the two variants below come out distinct and nonzero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Write the exit code to the `isa-debug-exit` port (0xf4), which shuts QEMU down
+/// immediately when run with `-device isa-debug-exit,iobase=0xf4,iosize=0x04`.
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    let mut port = port::Port::new(0xf4);
+    port.write(exit_code as u32 as u8);
+}
+
+#[cfg(test)]
+pub fn test_runner(tests: &[&dyn Fn()]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
@@ -0,0 +1,32 @@
+//the crate has no dependency that exposes the x86 `in`/`out` instructions yet, so this
+//gives the handful of places that talk to hardware registers (VGA CRTC, serial UART, ...)
+//a tiny, no_std-friendly port I/O abstraction instead of pulling one in
+
+use core::arch::asm;
+
+/// A single x86 I/O port, addressed by its port number.
+pub struct Port {
+    port: u16,
+}
+
+impl Port {
+    pub const fn new(port: u16) -> Port {
+        Port { port }
+    }
+
+    /// Write a byte to this port (the `out` instruction).
+    pub fn write(&mut self, value: u8) {
+        unsafe {
+            asm!("out dx, al", in("dx") self.port, in("al") value, options(nomem, nostack, preserves_flags));
+        }
+    }
+
+    /// Read a byte from this port (the `in` instruction).
+    pub fn read(&mut self) -> u8 {
+        let value: u8;
+        unsafe {
+            asm!("in al, dx", in("dx") self.port, out("al") value, options(nomem, nostack, preserves_flags));
+        }
+        value
+    }
+}
@@ -0,0 +1,89 @@
+//driver for the 16550 UART on COM1 (I/O port 0x3F8). QEMU's `-serial stdio` forwards this
+//port straight to the host terminal, which gives us an output channel that works even when
+//VGA text mode isn't up yet, and that an outside test harness can read
+
+use crate::port::Port;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+const COM1: u16 = 0x3F8;
+
+pub struct SerialPort {
+    data: Port,
+    interrupt_enable: Port,
+    fifo_control: Port,
+    line_control: Port,
+    modem_control: Port,
+    line_status: Port,
+}
+
+impl SerialPort {
+    fn new(base: u16) -> SerialPort {
+        SerialPort {
+            data: Port::new(base),
+            interrupt_enable: Port::new(base + 1),
+            fifo_control: Port::new(base + 2),
+            line_control: Port::new(base + 3),
+            modem_control: Port::new(base + 4),
+            line_status: Port::new(base + 5),
+        }
+    }
+
+    fn init(&mut self) {
+        self.interrupt_enable.write(0x00); //disable all interrupts
+        self.line_control.write(0x80); //enable DLAB to set the baud rate divisor
+        self.data.write(0x03); //divisor low byte -> 38400 baud
+        self.interrupt_enable.write(0x00); //divisor high byte
+        self.line_control.write(0x03); //8 bits, no parity, one stop bit, and clears DLAB
+        self.fifo_control.write(0xC7); //enable FIFO, clear it, 14-byte threshold
+        self.modem_control.write(0x0B); //DTR, RTS and OUT2 set
+    }
+
+    //bit 5 of the line status register is set once the transmit holding register is empty
+    fn transmit_ready(&mut self) -> bool {
+        self.line_status.read() & 0x20 != 0
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        while !self.transmit_ready() {}
+        self.data.write(byte);
+    }
+}
+
+impl core::fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    pub static ref SERIAL1: Mutex<SerialPort> = {
+        let mut serial_port = SerialPort::new(COM1);
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("printing to serial failed");
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(concat!($fmt, "\n"), $($arg)*));
+}
@@ -31,8 +31,20 @@ pub enum Color {
 struct ColorCode(u8); //contains u8 field
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
-        ColorCode((background as u8) << 4 | (foreground as u8))
+    //bit 7 of the attribute byte doubles as the blink flag, so the background nibble
+    //only has 3 usable bits here; mask it to stop a bright background (8-15) from
+    //silently flipping that bit and making the character blink
+    fn new_with_blink(foreground: Color, background: Color, blink: bool) -> ColorCode {
+        let blink_bit = if blink { 1 << 7 } else { 0 };
+        ColorCode(blink_bit | (background as u8 & 0b0111) << 4 | (foreground as u8))
+    }
+
+    fn with_blink(self, blink: bool) -> ColorCode {
+        if blink {
+            ColorCode(self.0 | 1 << 7)
+        } else {
+            ColorCode(self.0 & !(1 << 7))
+        }
     }
 }
 
@@ -52,18 +64,51 @@ const BUFFER_WIDTH: usize = 80;
 //so Buffer must have the memory layout of its member chars
 
 //The problem is that we only write to the Buffer and never read from it again. The compiler doesn't know that we really access VGA buffer memory (instead of normal RAM) and knows nothing about the side effect that some characters appear on the screen. So it might decide that these writes are unnecessary and can be omitted. To avoid this erroneous optimization, we need to specify these writes as volatile. This tells the compiler that the write has side effects and should not be optimized away.
+use crate::port::Port;
 use volatile::Volatile;
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+//the states of the small ANSI SGR ("\x1b[...m") parser embedded in write_byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnsiState {
+    Normal,
+    //just saw the ESC byte (0x1b)
+    Escape,
+    //saw ESC '[' and are collecting decimal params separated by ';'
+    Params,
+}
+
+//no heap is available, so the SGR params collected between '[' and 'm' are kept in a
+//small fixed-size array instead of a Vec; sequences with more params than this are
+//simply truncated
+const MAX_SGR_PARAMS: usize = 8;
+
 pub struct Writer {
     column_position: usize,
     row_position: usize,
     color_code: ColorCode,
     // 'static lifetime specifies that the refenence is valid for the whole program
     buffer: &'static mut Buffer,
+    ansi_state: AnsiState,
+    ansi_params: [u16; MAX_SGR_PARAMS],
+    ansi_params_len: usize,
+    ansi_current_param: u16,
+    //VGA CRT controller index/data ports, used to keep the hardware cursor in sync
+    //with (row_position, column_position)
+    cursor_index_port: Port,
+    cursor_data_port: Port,
+    //plain-RAM mirror of the VGA buffer; write_byte/new_line/shift_up/clear_row all
+    //mutate this (cheap, cacheable) instead of the memory-mapped hardware directly,
+    //and flush()/flush_dirty() push it out with volatile writes when we're ready to
+    //actually display it
+    shadow: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    //[dirty_from, dirty_to] is the inclusive row range touched since the last flush;
+    //dirty_from > dirty_to means nothing is dirty
+    dirty_from: usize,
+    dirty_to: usize,
 }
 
 //* static and constant variables are initialized at compile time which means all its member should be initialized with constant value or function. ColorCode::new can be const function but the real problem happens with derefernce raw pointer
@@ -78,39 +123,127 @@ lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
         row_position: 0,
-        color_code: ColorCode::new(Color::Black, Color::White),
+        color_code: ColorCode::new_with_blink(Color::Black, Color::White, false),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        ansi_state: AnsiState::Normal,
+        ansi_params: [0; MAX_SGR_PARAMS],
+        ansi_params_len: 0,
+        ansi_current_param: 0,
+        cursor_index_port: Port::new(0x3D4),
+        cursor_data_port: Port::new(0x3D5),
+        shadow: [[ScreenChar {
+            ascii_character: b' ',
+            color_code: ColorCode::new_with_blink(Color::Black, Color::White, false),
+        }; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        dirty_from: 0,
+        dirty_to: BUFFER_HEIGHT - 1,
     });
 }
 
 impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
+        match self.ansi_state {
+            AnsiState::Normal => self.write_byte_normal(byte),
+            AnsiState::Escape => self.write_byte_escape(byte),
+            AnsiState::Params => self.write_byte_params(byte),
+        }
+    }
+
+    fn write_byte_normal(&mut self, byte: u8) {
         match byte {
+            0x1b => self.ansi_state = AnsiState::Escape,
             b'\n' => self.new_line(),
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
                 }
-                //after using volatile:Instead of a normal assignment using =, we're now using the write method. This guarantees that the compiler will never optimize away this write.
                 //bound checks are done by default in rust as we specified chars dimensions so no worries about writing outside the buffer
-                self.buffer.chars[self.row_position][self.column_position].write(ScreenChar {
+                self.shadow[self.row_position][self.column_position] = ScreenChar {
                     ascii_character: byte,
                     color_code: self.color_code,
-                });
+                };
+                self.mark_dirty(self.row_position);
 
                 self.column_position += 1;
             }
         }
+        self.update_cursor();
     }
 
-    pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                //printable ASCII byte or newline , as str is utf-8 which means some characters needs two bytes but in vga only one byte is available for a char
-                // | separates between multiple patterns to match
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+    //we only support the CSI ("[") introducer; any other byte after ESC means the ESC
+    //itself was not the start of a sequence we understand, so drop only the ESC and let
+    //this byte print normally instead of swallowing it too
+    fn write_byte_escape(&mut self, byte: u8) {
+        if byte == b'[' {
+            self.ansi_params = [0; MAX_SGR_PARAMS];
+            self.ansi_params_len = 0;
+            self.ansi_current_param = 0;
+            self.ansi_state = AnsiState::Params;
+        } else {
+            self.ansi_state = AnsiState::Normal;
+            self.write_byte_normal(byte);
+        }
+    }
+
+    fn write_byte_params(&mut self, byte: u8) {
+        match byte {
+            b'0'..=b'9' => {
+                self.ansi_current_param = self
+                    .ansi_current_param
+                    .saturating_mul(10)
+                    .saturating_add((byte - b'0') as u16);
+            }
+            b';' => self.push_ansi_param(),
+            b'm' => {
+                self.push_ansi_param();
+                self.apply_sgr();
+                self.ansi_state = AnsiState::Normal;
             }
+            //anything else is a malformed or unsupported sequence; drop it
+            _ => self.ansi_state = AnsiState::Normal,
+        }
+    }
+
+    fn push_ansi_param(&mut self) {
+        if self.ansi_params_len < MAX_SGR_PARAMS {
+            self.ansi_params[self.ansi_params_len] = self.ansi_current_param;
+            self.ansi_params_len += 1;
+        }
+        self.ansi_current_param = 0;
+    }
+
+    //apply the collected SGR codes to color_code, resetting to the lazy_static default on 0
+    fn apply_sgr(&mut self) {
+        //code 0 only resets the colors, not whatever blink state set_blink put in place
+        let blink = self.color_code.0 & (1 << 7) != 0;
+        let mut foreground = color_from_u8(self.color_code.0 & 0x0f);
+        let mut background = color_from_u8((self.color_code.0 >> 4) & 0x0f);
+
+        for &code in &self.ansi_params[..self.ansi_params_len] {
+            match code {
+                0 => {
+                    foreground = Color::Black;
+                    background = Color::White;
+                }
+                1 => foreground = brighten(foreground),
+                30..=37 => foreground = ansi_base_color(code - 30),
+                40..=47 => background = ansi_base_color(code - 40),
+                //unsupported SGR code; ignore it
+                _ => {}
+            }
+        }
+
+        //new_with_blink masks the background to 3 bits, so a bright background (e.g. the
+        //White from a "0" reset) can't silently flip the blink bit back on
+        self.color_code = ColorCode::new_with_blink(foreground, background, blink);
+    }
+
+    pub fn write_string(&mut self, s: &str) {
+        //iterate by char (not by byte) since the buffer uses code page 437, not plain
+        //ASCII, so a multi-byte UTF-8 sequence should become one CP437 glyph, not several
+        //mangled bytes
+        for c in s.chars() {
+            self.write_byte(char_to_cp437(c));
         }
     }
 
@@ -121,19 +254,59 @@ impl Writer {
             self.row_position = BUFFER_HEIGHT - 1;
         }
         self.column_position = 0;
+        self.update_cursor();
     }
 
     /// Shift the VGA content one line up
     ///
     ///
     fn shift_up(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                self.buffer.chars[row - 1][col].write(self.buffer.chars[row][col].read());
-            }
-        }
+        self.shadow.copy_within(1..BUFFER_HEIGHT, 0);
         //clear last line
         self.clear_row(BUFFER_HEIGHT - 1);
+        //copy_within touched every row, so the whole screen needs to reach hardware
+        self.dirty_from = 0;
+        self.dirty_to = BUFFER_HEIGHT - 1;
+    }
+
+    /// Toggle the blink attribute on the character color currently in use, keeping the
+    /// foreground/background colors as they are.
+    pub fn set_blink(&mut self, blink: bool) {
+        self.color_code = self.color_code.with_blink(blink);
+    }
+
+    /// Change the foreground/background colors, preserving the current blink state.
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        let blink = self.color_code.0 & (1 << 7) != 0;
+        self.color_code = ColorCode::new_with_blink(foreground, background, blink);
+    }
+
+    /// Program the VGA CRT controller so the blinking hardware cursor follows
+    /// (row_position, column_position).
+    pub fn update_cursor(&mut self) {
+        let pos = self.row_position * BUFFER_WIDTH + self.column_position;
+
+        self.cursor_index_port.write(0x0F);
+        self.cursor_data_port.write((pos & 0xFF) as u8);
+        self.cursor_index_port.write(0x0E);
+        self.cursor_data_port.write(((pos >> 8) & 0xFF) as u8);
+    }
+
+    /// Turn the hardware cursor on and set its scanline range (0-15, top to bottom).
+    pub fn enable_cursor(&mut self, start_scanline: u8, end_scanline: u8) {
+        self.cursor_index_port.write(0x0A);
+        let current = self.cursor_data_port.read();
+        self.cursor_data_port.write((current & 0xC0) | start_scanline);
+
+        self.cursor_index_port.write(0x0B);
+        let current = self.cursor_data_port.read();
+        self.cursor_data_port.write((current & 0xE0) | end_scanline);
+    }
+
+    /// Turn the hardware cursor off (bit 5 of the "cursor start" register).
+    pub fn disable_cursor(&mut self) {
+        self.cursor_index_port.write(0x0A);
+        self.cursor_data_port.write(0x20);
     }
 
     fn clear_row(&mut self, row: usize) {
@@ -141,12 +314,177 @@ impl Writer {
             ascii_character: b' ',
             color_code: self.color_code,
         };
-        for col in 0..BUFFER_HEIGHT {
-            self.buffer.chars[row][col].write(blank);
+        for col in 0..BUFFER_WIDTH {
+            self.shadow[row][col] = blank;
+        }
+        self.mark_dirty(row);
+    }
+
+    fn mark_dirty(&mut self, row: usize) {
+        if self.dirty_from > self.dirty_to {
+            self.dirty_from = row;
+            self.dirty_to = row;
+        } else {
+            self.dirty_from = self.dirty_from.min(row);
+            self.dirty_to = self.dirty_to.max(row);
+        }
+    }
+
+    /// Push the whole shadow buffer out to VGA memory with volatile writes.
+    pub fn flush(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                self.buffer.chars[row][col].write(self.shadow[row][col]);
+            }
         }
+        //nothing dirty is left: dirty_from > dirty_to
+        self.dirty_from = 1;
+        self.dirty_to = 0;
+    }
+
+    /// Push only the rows touched since the last flush out to VGA memory.
+    pub fn flush_dirty(&mut self) {
+        if self.dirty_from > self.dirty_to {
+            return;
+        }
+        for row in self.dirty_from..=self.dirty_to {
+            for col in 0..BUFFER_WIDTH {
+                self.buffer.chars[row][col].write(self.shadow[row][col]);
+            }
+        }
+        self.dirty_from = 1;
+        self.dirty_to = 0;
+    }
+}
+
+//map a Unicode scalar value onto its code page 437 byte. Printable ASCII maps one-to-one,
+//a handful of box-drawing/block/accented characters map onto the high half of the table,
+//and anything else falls back to the 0xfe block like before
+fn char_to_cp437(c: char) -> u8 {
+    match c {
+        '\n' => b'\n',
+        //ESC kicks off the ANSI SGR state machine in write_byte, so it must reach it
+        //unchanged instead of falling into the 0xfe fallback below
+        '\x1b' => 0x1b,
+        ' '..='~' => c as u8,
+        //block elements
+        '░' => 0xB0,
+        '▒' => 0xB1,
+        '▓' => 0xB2,
+        '█' => 0xDB,
+        '▄' => 0xDC,
+        '▌' => 0xDD,
+        '▐' => 0xDE,
+        '▀' => 0xDF,
+        //single-line box drawing
+        '│' => 0xB3,
+        '┤' => 0xB4,
+        '┐' => 0xBF,
+        '└' => 0xC0,
+        '┴' => 0xC1,
+        '┬' => 0xC2,
+        '├' => 0xC3,
+        '─' => 0xC4,
+        '┼' => 0xC5,
+        '┘' => 0xD9,
+        '┌' => 0xDA,
+        //double-line box drawing and single/double mixes
+        '╡' => 0xB5,
+        '╢' => 0xB6,
+        '╖' => 0xB7,
+        '╕' => 0xB8,
+        '╣' => 0xB9,
+        '║' => 0xBA,
+        '╗' => 0xBB,
+        '╝' => 0xBC,
+        '╜' => 0xBD,
+        '╛' => 0xBE,
+        '╞' => 0xC6,
+        '╟' => 0xC7,
+        '╚' => 0xC8,
+        '╔' => 0xC9,
+        '╩' => 0xCA,
+        '╦' => 0xCB,
+        '╠' => 0xCC,
+        '═' => 0xCD,
+        '╬' => 0xCE,
+        '╧' => 0xCF,
+        '╨' => 0xD0,
+        '╤' => 0xD1,
+        '╥' => 0xD2,
+        '╙' => 0xD3,
+        '╘' => 0xD4,
+        '╒' => 0xD5,
+        '╓' => 0xD6,
+        '╫' => 0xD7,
+        '╪' => 0xD8,
+        //common accented letters
+        'ü' => 0x81,
+        'é' => 0x82,
+        'â' => 0x83,
+        'ä' => 0x84,
+        'à' => 0x85,
+        'ç' => 0x87,
+        'ê' => 0x88,
+        'ë' => 0x89,
+        'è' => 0x8A,
+        'î' => 0x8C,
+        'ï' => 0x8B,
+        'ì' => 0x8D,
+        'ô' => 0x93,
+        'ö' => 0x94,
+        'ò' => 0x95,
+        'û' => 0x96,
+        'ù' => 0x97,
+        'ñ' => 0xA4,
+        'á' => 0xA0,
+        _ => 0xfe,
     }
 }
 
+//reverse of `Color as u8`, needed to read back the foreground/background the ANSI
+//parser is adjusting from the current color_code byte
+fn color_from_u8(n: u8) -> Color {
+    match n & 0x0f {
+        0 => Color::Black,
+        1 => Color::Blue,
+        2 => Color::Green,
+        3 => Color::Cyan,
+        4 => Color::Red,
+        5 => Color::Magenta,
+        6 => Color::Brown,
+        7 => Color::LightGray,
+        8 => Color::DarkGray,
+        9 => Color::LightBlue,
+        10 => Color::LightGreen,
+        11 => Color::LightCyan,
+        12 => Color::LightRed,
+        13 => Color::Pink,
+        14 => Color::Yellow,
+        _ => Color::White,
+    }
+}
+
+//maps the standard ANSI color order (black, red, green, yellow, blue, magenta, cyan,
+//white) used by SGR codes 30-37/40-47 onto this crate's Color enum
+fn ansi_base_color(n: u16) -> Color {
+    match n {
+        0 => Color::Black,
+        1 => Color::Red,
+        2 => Color::Green,
+        3 => Color::Brown,
+        4 => Color::Blue,
+        5 => Color::Magenta,
+        6 => Color::Cyan,
+        _ => Color::LightGray,
+    }
+}
+
+//SGR code 1 sets the VGA "bright" bit (bit 3) on whatever foreground is active
+fn brighten(color: Color) -> Color {
+    color_from_u8(color as u8 | 0x08)
+}
+
 use core::fmt;
 //to use formatting macros we should implement Write trait which only contains method write_str
 //now we can use write! and
@@ -178,8 +516,11 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
+    let mut writer = WRITER.lock();
     //unwrap panics if error happens which won't ever happen as we always return ok(()) in write_str which is used by write_fmt
-    WRITER.lock().write_fmt(args).unwrap();
+    writer.write_fmt(args).unwrap();
+    //push the rows touched since the last flush out to hardware so output still appears immediately
+    writer.flush_dirty();
 }
 
 //* we can access writer directly from main as we made a global static instance of writer so we do not need have to use this function anymore
@@ -187,8 +528,20 @@ pub fn print_something() {
     let mut writer = Writer {
         column_position: 0,
         row_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
+        color_code: ColorCode::new_with_blink(Color::Yellow, Color::Black, false),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        ansi_state: AnsiState::Normal,
+        ansi_params: [0; MAX_SGR_PARAMS],
+        ansi_params_len: 0,
+        ansi_current_param: 0,
+        cursor_index_port: Port::new(0x3D4),
+        cursor_data_port: Port::new(0x3D5),
+        shadow: [[ScreenChar {
+            ascii_character: b' ',
+            color_code: ColorCode::new_with_blink(Color::Yellow, Color::Black, false),
+        }; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        dirty_from: 0,
+        dirty_to: BUFFER_HEIGHT - 1,
     };
 
     writer.write_string("A\nB\nC\nD\nE\nF\nG\nG\nW\nX\nY\nZ\n1\n2\n3\n4\n5\n6\n7\n8\n\n\n\n\n");
@@ -198,4 +551,72 @@ pub fn print_something() {
     use core::fmt::Write;
     //write return Result but we do  not use it so to remove compiler warning of unused return, we call unwrap which panics if error happened
     write!(writer, "the numbers are {} and {}\n\n", 42, 10 / 3).unwrap();
+
+    writer.flush();
+}
+
+#[cfg(test)]
+#[test_case]
+fn test_new_with_blink_masks_bright_background() {
+    //White (15) has bit 3 set; new_with_blink must mask that out of the background
+    //nibble so it can't be mistaken for the blink bit
+    let steady = ColorCode::new_with_blink(Color::Black, Color::White, false);
+    assert_eq!(steady.0, 0x70);
+
+    let blinking = ColorCode::new_with_blink(Color::Black, Color::White, true);
+    assert_eq!(blinking.0, 0xF0);
+}
+
+#[cfg(test)]
+#[test_case]
+fn test_set_blink_and_set_color_round_trip() {
+    let mut writer = WRITER.lock();
+    //this test mutates the shared WRITER's color_code, so save/restore it like the scroll
+    //test forces its own scroll instead of leaning on whatever a previous test left behind
+    let original_color_code = writer.color_code;
+
+    writer.set_color(Color::Green, Color::LightGray);
+    writer.set_blink(true);
+    assert_eq!(writer.color_code.0, 0xF2);
+
+    writer.set_blink(false);
+    assert_eq!(writer.color_code.0, 0x72);
+
+    writer.color_code = original_color_code;
+}
+
+#[cfg(test)]
+#[test_case]
+fn test_cp437_mapping() {
+    assert_eq!(char_to_cp437('A'), b'A');
+    assert_eq!(char_to_cp437('█'), 0xDB);
+    assert_eq!(char_to_cp437('\n'), b'\n');
+    //no CP437 equivalent, falls back to the block glyph
+    assert_eq!(char_to_cp437('☺'), 0xfe);
+}
+
+#[cfg(test)]
+#[test_case]
+fn test_println_many_lines_does_not_panic() {
+    for _ in 0..BUFFER_HEIGHT * 2 {
+        println!("test_println_many_lines_does_not_panic output");
+    }
+}
+
+#[cfg(test)]
+#[test_case]
+fn test_println_output_appears_on_last_line() {
+    //force a scroll ourselves instead of relying on another test having left WRITER at
+    //the bottom of the screen; one full screen of filler plus our line guarantees it
+    //lands on BUFFER_HEIGHT - 2 regardless of test order
+    for _ in 0..BUFFER_HEIGHT {
+        println!("filler line to force a scroll");
+    }
+    let s = "Some test string that fits on a single line";
+    println!("{}", s);
+    let writer = WRITER.lock();
+    for (i, c) in s.chars().enumerate() {
+        let screen_char = writer.shadow[BUFFER_HEIGHT - 2][i];
+        assert_eq!(char::from(screen_char.ascii_character), c);
+    }
 }